@@ -0,0 +1,22 @@
+-- Tables owned by baypaw rather than the site. Apply this to the fateslist
+-- database before starting baypaw, it is safe to run again on upgrades
+
+-- The audit log of every flag change baypaw makes
+CREATE TABLE IF NOT EXISTS baypaw_flag_audit (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    flag INTEGER NOT NULL,
+    action TEXT NOT NULL,
+    role_id BIGINT,
+    source TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS baypaw_flag_audit_user_id ON baypaw_flag_audit (user_id, created_at DESC);
+
+-- Flags of roles with revoke_on_leave off that users kept when they left the
+-- main server. They keep these after rejoining until they get the role again
+CREATE TABLE IF NOT EXISTS baypaw_retained_flags (
+    user_id BIGINT NOT NULL,
+    flag INTEGER NOT NULL,
+    PRIMARY KEY (user_id, flag)
+);
@@ -0,0 +1,104 @@
+// Shared-secret authentication for the IPC endpoints
+//
+// Every request must carry `Authorization: Bearer <secret>` where the secret
+// is the `baypaw_secret` key in secrets.json. Anything else gets a 401, apart
+// from the health checks so they can be probed without the secret
+use crate::error::ApiError;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::Error;
+use log::warn;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+/// Paths that don't need the secret
+const PUBLIC_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+pub struct SharedSecret {
+    secret: Rc<String>,
+}
+
+impl SharedSecret {
+    pub fn new(secret: String) -> Self {
+        SharedSecret {
+            secret: Rc::new(secret),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for SharedSecret
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SharedSecretMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(SharedSecretMiddleware {
+            service,
+            secret: self.secret.clone(),
+        }))
+    }
+}
+
+pub struct SharedSecretMiddleware<S> {
+    service: S,
+    secret: Rc<String>,
+}
+
+impl<S, B> Service<ServiceRequest> for SharedSecretMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        if PUBLIC_PATHS.contains(&req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+
+        let token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+
+        let authorized = match token {
+            Some(token) => constant_time_eq(token.as_bytes(), self.secret.as_bytes()),
+            None => false,
+        };
+
+        if !authorized {
+            warn!(
+                "Rejecting unauthenticated request to {} from {:?}",
+                req.path(),
+                req.peer_addr()
+            );
+            return Box::pin(async { Err(ApiError::unauthorized().into()) });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(fut)
+    }
+}
+
+/// Compares two byte strings without bailing out on the first mismatch so the
+/// secret can't be guessed one byte at a time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
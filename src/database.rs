@@ -1,29 +1,38 @@
+use crate::config::{self, LiveConfig};
+use crate::flags;
+use crate::health::BotState;
+use crate::metrics::{GetchTier, Metrics};
+use crate::reporter::{ErrorReport, ErrorReporter};
+use crate::supervisor::{self, Bot, Sharding};
+use arc_swap::ArcSwap;
 use bristlefrost::models::Status;
-use deadpool_redis::{Config, Runtime};
+use deadpool_redis::redis::AsyncCommands;
+use deadpool_redis::{Config, PoolConfig, Runtime, Timeouts};
+use futures::stream::{self, StreamExt};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use serenity::async_trait;
 use serenity::builder::CreateInvite;
+use serenity::http::HttpError;
 use serenity::json as sjson;
 use serenity::model::gateway::GatewayIntents;
 use serenity::model::guild::Member;
-use serenity::model::id::{ChannelId, RoleId};
+use serenity::model::id::RoleId;
 use serenity::model::prelude::{GuildId, Ready, UserId};
 use serenity::model::user::{OnlineStatus, User};
 use serenity::prelude::*;
 use sqlx::postgres::PgPoolOptions;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::sync::atomic;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::task;
 
 pub struct Clients {
-    pub main: Arc<serenity::CacheAndHttp>,
-    pub servers: Arc<serenity::CacheAndHttp>,
-    pub fetcher: serenity::http::Http,
+    pub main: Arc<Bot>,
+    pub servers: Arc<Bot>,
+    pub fetcher: Arc<serenity::http::Http>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -40,26 +49,36 @@ pub struct NormalRole {
     pub id: u64,
     pub flag: i32,
     pub fname: String,
+    /// Whether users lose this flag when they leave the main server
+    #[serde(default = "default_revoke_on_leave")]
+    pub revoke_on_leave: bool,
+}
+
+fn default_revoke_on_leave() -> bool {
+    true
 }
 
 pub struct Database {
     pub redis: deadpool_redis::Pool,
     pub pool: sqlx::PgPool,
     pub clis: Clients,
-    pub staff_roles: HashMap<String, StaffRole>,
-    pub normal_roles: HashMap<String, NormalRole>,
-    pub discord: Discord,
-    /// staffRoleCache maps the ID to its key
-    pub staff_roles_cache: HashMap<u64, String>,
-    /// normalRoleCache maps the ID to its key
-    pub normal_roles_cache: HashMap<u64, String>,
+    /// Roles and discord config, shared with MainHandler and swapped out on SIGHUP
+    pub live: Arc<ArcSwap<LiveConfig>>,
+    /// Shared secret IPC callers must send as a bearer token
+    pub secret: String,
+    pub config: config::Config,
+    pub reporter: Arc<ErrorReporter>,
+    pub metrics: Arc<Metrics>,
+    /// Set if flag sync is in dry run mode
+    pub dry_run: Option<Arc<flags::DryRunLog>>,
 }
 
 #[derive(Deserialize, Clone)]
-struct BaypawTokens {
+pub struct BaypawTokens {
     token_main: String,
     token_squirrelflight: String,
     token_fetch_bot_1: String,
+    pub baypaw_secret: String,
 }
 
 #[derive(Deserialize, Clone)]
@@ -84,371 +103,507 @@ pub struct IUser {
     pub status: Status,
 }
 
-struct MainHandler {
-    normal_roles: HashMap<String, NormalRole>,
-    pool: sqlx::PgPool,
-    discord: Discord,
+/// Squirrelflight only needs to tell us when it is ready
+struct ServerHandler {
+    state: Arc<BotState>,
 }
 
 #[async_trait]
-impl EventHandler for MainHandler {
+impl EventHandler for ServerHandler {
     async fn ready(&self, _ctx: Context, ready: Ready) {
         debug!("{} is connected!", ready.user.name);
+
+        self.state.ready.store(true, atomic::Ordering::Relaxed);
     }
+}
 
-    async fn guild_member_update(&self, ctx: Context, _: Option<Member>, new: Member) {
-        let mut added_flags = Vec::new();
+struct MainHandler {
+    state: Arc<BotState>,
+    flags: Arc<flags::FlagSync>,
+}
+
+impl MainHandler {
+    /// Syncs the flags of a user who joined, left or was updated on the main server
+    async fn sync_member(
+        &self,
+        user_id: UserId,
+        roles: Option<&[RoleId]>,
+        source: flags::SyncSource,
+    ) {
+        let live = self.flags.live.load_full();
+
+        let res = flags::sync_member(
+            &self.flags.pool,
+            &live,
+            user_id,
+            roles,
+            source,
+            self.flags.dry_run.as_deref(),
+        )
+        .await;
 
-        for (name, role) in &self.normal_roles {
-            if added_flags.contains(&role.flag) {
-                // Aready dealt with this flag
-                continue;
+        match res {
+            Ok(flags::SyncOutcome::Updated { old, new }) => {
+                debug!("Updated flags of {}: {:?} -> {:?}", user_id, old, new);
+            }
+            Ok(flags::SyncOutcome::WouldUpdate) => {
+                // Already logged by the dry run log
+            }
+            Ok(flags::SyncOutcome::Unchanged) => {
+                debug!("Flags of {} are already up to date", user_id);
             }
+            Ok(flags::SyncOutcome::NotRegistered) => {
+                // Nothing to do, they'll get their flags from the next reconciliation after they log in
+                debug!("{} is not in users, skipping flag sync", user_id);
+            }
+            Err(err) => {
+                let roles = match roles {
+                    Some(roles) => format!("{:?}", roles.iter().map(|r| r.0).collect::<Vec<_>>()),
+                    None => "Not on main server".to_string(),
+                };
+
+                self.flags.metrics.flag_sync_failed(source);
+                self.flags.reporter.report(
+                    ErrorReport::new("Error occured when updating flags", err)
+                        .field("User", user_id)
+                        .field("Roles", roles),
+                );
+            }
+        }
+    }
+}
 
-            debug!("Checking if user has {}", name);
-
-            if new.roles.contains(&RoleId(role.id)) {
-                debug!("User has {}", name);
-                // Remove old flags first
-                let res = sqlx::query!(
-                    "SELECT flags FROM users WHERE user_id = $1",
-                    new.user.id.0 as i64
-                )
-                .fetch_one(&self.pool)
-                .await;
-
-                if let Err(err) = res {
-                    let site_errors_channel =
-                        self.discord.channels.site_errors_channel.parse::<u64>();
-
-                    error!("Error updating user flags: {}", err);
-
-                    if let Ok(site_errors_channel) = site_errors_channel {
-                        let channel = ChannelId(site_errors_channel);
-                        let _ = channel
-                            .say(
-                                &ctx,
-                                format!("Error occured when updating flags: ```rs\n{:?}```", err),
-                            )
-                            .await;
-                    }
-                } else if let Ok(flags) = res {
-                    added_flags.push(role.flag);
+#[async_trait]
+impl EventHandler for MainHandler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        debug!("{} is connected!", ready.user.name);
 
-                    if flags.flags.contains(&role.flag) {
-                        debug!("User already has {}", name);
-                        continue;
-                    }
+        self.state.ready.store(true, atomic::Ordering::Relaxed);
 
-                    debug!("User does not have {}. Adding now", name);
-
-                    let res = sqlx::query!(
-                        "UPDATE users SET flags = array_remove(flags, $1) WHERE user_id = $2",
-                        role.flag,
-                        new.user.id.0 as i64
-                    )
-                    .execute(&self.pool)
-                    .await;
-
-                    if let Err(err) = res {
-                        let site_errors_channel =
-                            self.discord.channels.site_errors_channel.parse::<u64>();
-
-                        error!("Error updating user flags: {}", err);
-
-                        if let Ok(site_errors_channel) = site_errors_channel {
-                            let channel = ChannelId(site_errors_channel);
-                            let err = channel
-                                .say(
-                                    &ctx,
-                                    format!(
-                                        "Error occured when updating flags: ```rs\n{:?}```",
-                                        err
-                                    ),
-                                )
-                                .await;
-
-                            if let Err(e) = err {
-                                error!("Error sending message to Discord, {:?}", e);
-                            }
-                        }
-                    }
+        // Catch up on any role changes we missed while we were down
+        let flags = self.flags.clone();
+        task::spawn(async move { flags.reconcile_and_log(ctx.http).await });
+    }
 
-                    let res = sqlx::query!(
-                        "UPDATE users SET flags = array_append(flags, $1) WHERE user_id = $2",
-                        role.flag,
-                        new.user.id.0 as i64
-                    )
-                    .execute(&self.pool)
-                    .await;
-
-                    if let Err(err) = res {
-                        let site_errors_channel =
-                            self.discord.channels.site_errors_channel.parse::<u64>();
-
-                        error!("Error updating user flags: {}", err);
-
-                        if let Ok(site_errors_channel) = site_errors_channel {
-                            let channel = ChannelId(site_errors_channel);
-                            let err = channel
-                                .say(
-                                    &ctx,
-                                    format!(
-                                        "Error occured when updating flags: ```rs\n{:?}```",
-                                        err
-                                    ),
-                                )
-                                .await;
-
-                            if let Err(e) = err {
-                                error!("Error sending message to Discord, {:?}", e);
-                            }
-                        }
-                    }
-                }
-            } else {
-                debug!("User does not have {}", name);
-                let res = sqlx::query!(
-                    "UPDATE users SET flags = array_remove(flags, $1) WHERE user_id = $2",
-                    role.flag,
-                    new.user.id.0 as i64
-                )
-                .execute(&self.pool)
-                .await;
-
-                if let Err(err) = res {
-                    let site_errors_channel =
-                        self.discord.channels.site_errors_channel.parse::<u64>();
-
-                    error!("Error updating user flags: {}", err);
-
-                    if let Ok(site_errors_channel) = site_errors_channel {
-                        let channel = ChannelId(site_errors_channel);
-                        let err = channel
-                            .say(
-                                &ctx,
-                                format!("Error occured when updating flags: ```rs\n{:?}```", err),
-                            )
-                            .await;
-
-                        if let Err(e) = err {
-                            error!("Error sending message to Discord, {:?}", e);
-                        }
-                    }
-                }
-            }
+    async fn guild_member_addition(&self, _ctx: Context, new_member: Member) {
+        let main = self.flags.live.load().discord.servers.main;
+
+        if new_member.guild_id != main {
+            return;
         }
+
+        self.sync_member(
+            new_member.user.id,
+            Some(&new_member.roles),
+            flags::SyncSource::MemberJoin,
+        )
+        .await;
     }
-}
 
-impl Database {
-    pub async fn new() -> Self {
-        const MAX_CONNECTIONS: u32 = 3; // max connections to the database, we don't need too many here
+    async fn guild_member_removal(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        user: User,
+        _: Option<Member>,
+    ) {
+        let main = self.flags.live.load().discord.servers.main;
+
+        if guild_id != main {
+            return;
+        }
 
-        let pool = PgPoolOptions::new()
-            .max_connections(MAX_CONNECTIONS)
-            .connect("postgres://localhost/fateslist")
-            .await
-            .expect("Could not initialize connection");
+        self.sync_member(user.id, None, flags::SyncSource::MemberLeave)
+            .await;
+    }
 
-        info!("Connected to database");
+    async fn guild_member_update(&self, _ctx: Context, _: Option<Member>, new: Member) {
+        let main = self.flags.live.load().discord.servers.main;
+
+        if new.guild_id != main {
+            return;
+        }
+
+        self.sync_member(
+            new.user.id,
+            Some(&new.roles),
+            flags::SyncSource::MemberUpdate,
+        )
+        .await;
+    }
+}
+
+/// Every role a user has on the main server that we know about
+#[derive(Serialize)]
+pub struct AllRoles {
+    pub staff_roles: Vec<StaffRole>,
+    pub normal_roles: Vec<NormalRole>,
+}
+
+/// The result of looking up roles for many users at once
+#[derive(Serialize)]
+pub struct BatchRoles<T> {
+    pub results: HashMap<u64, T>,
+    /// Users that aren't in our main server cache. They are given the user role
+    /// in results but may actually have other roles
+    pub not_cached: Vec<u64>,
+}
 
-        let cfg = Config::from_url("redis://localhost:1001/1");
-        let path = match env::var_os("HOME") {
-            None => {
-                panic!("$HOME not set");
+fn highest_staff_role<'a>(live: &'a LiveConfig, roles: &[RoleId]) -> &'a StaffRole {
+    let mut perms = live.staff_roles.get("user").unwrap();
+
+    /*
+     * Iterate over every role the member has and check if its in staff_role_cache or not
+     * This is also more optimized than looping over all of staff_roles as we only loop
+     * over all roles once
+     */
+    for role in roles {
+        if let Some(possible_perm) = live.staff_roles_cache.get(&role.0) {
+            let possible = live.staff_roles.get(possible_perm).unwrap();
+            if possible.perm > perms.perm {
+                perms = possible;
             }
-            Some(path) => PathBuf::from(path),
-        };
+        }
+    }
 
-        let data_dir = path.into_os_string().into_string().unwrap() + "/FatesList/config/data/";
-        debug!("Got data dir: {}", data_dir);
+    perms
+}
 
-        let mut file =
-            File::open(data_dir.to_owned() + "secrets.json").expect("No config file found");
-        let mut discord = String::new();
-        file.read_to_string(&mut discord).unwrap();
+fn highest_normal_role<'a>(live: &'a LiveConfig, roles: &[RoleId]) -> &'a NormalRole {
+    let mut flags = live.normal_roles.get("user").unwrap();
 
-        let tokens: BaypawTokens =
-            serde_json::from_str(&discord).expect("secrets.json was not well-formatted");
+    /*
+     * Iterate over every role the member has and check if its in normal_role_cache or not
+     * This is also more optimized than looping over all of normal_roles as we only loop
+     * over all roles once
+     */
+    for role in roles {
+        if let Some(possible_flag) = live.normal_roles_cache.get(&role.0) {
+            let possible = live.normal_roles.get(possible_flag).unwrap();
 
-        let mut staff_file = File::open(data_dir.to_owned() + "staff_roles.json")
-            .expect("No staff roles file found");
-        let mut staff_str = String::new();
-        staff_file.read_to_string(&mut staff_str).unwrap();
+            if possible.flag > flags.flag {
+                flags = possible;
+            }
+        }
+    }
 
-        let staff_roles: HashMap<String, StaffRole> =
-            serde_json::from_str(&staff_str).expect("staff_roles.json was not well-formatted");
+    flags
+}
 
-        let mut normal_roles_file =
-            File::open(data_dir.to_owned() + "roles.json").expect("No normal roles file found");
-        let mut normal_str = String::new();
-        normal_roles_file.read_to_string(&mut normal_str).unwrap();
+/// Redis key users fetched by getch are cached under
+fn getch_key(id: u64) -> String {
+    format!("baypaw:getch:{}", id)
+}
 
-        let normal_roles: HashMap<String, NormalRole> =
-            serde_json::from_str(&normal_str).expect("roles.json was not well-formatted");
+/// Whether Discord told us the thing we asked for doesn't exist
+fn is_not_found(err: &HttpError) -> bool {
+    match err {
+        HttpError::UnsuccessfulRequest(resp) => resp.status_code.as_u16() == 404,
+        _ => false,
+    }
+}
 
-        let mut staff_roles_cache = HashMap::new();
+impl Database {
+    pub async fn new(config: &config::Config, files: config::DataFiles) -> Self {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.postgres_max_connections)
+            .connect_timeout(Duration::from_secs(config.postgres_connect_timeout))
+            .connect(&config.postgres_url)
+            .await
+            .expect("Could not initialize connection");
 
-        let mut normal_roles_cache = HashMap::new();
+        info!("Connected to database");
 
-        // This is needed to create a bi-directional cache allowing the mapping of role ids to keys as well as keys to role ids
-        for (key, role) in &staff_roles {
-            // This sort of copying is rather cheap
-            staff_roles_cache.insert(role.id.parse::<u64>().unwrap(), key.clone());
-        }
+        let mut cfg = Config::from_url(&config.redis_url);
+        cfg.pool = Some(PoolConfig {
+            max_size: config.redis_pool_size,
+            timeouts: Timeouts {
+                wait: Some(Duration::from_secs(config.redis_connect_timeout)),
+                create: Some(Duration::from_secs(config.redis_connect_timeout)),
+                recycle: None,
+            },
+        });
 
-        // This is needed to create a bi-directional cache allowing the mapping of role ids to keys as well as keys to role ids
-        for (key, role) in &normal_roles {
-            // This sort of copying is rather cheap
-            normal_roles_cache.insert(role.id, key.clone());
-        }
+        let tokens = files.tokens;
+        let live = Arc::new(ArcSwap::from_pointee(files.live));
 
-        let mut discord_file =
-            File::open(data_dir.to_owned() + "discord.json").expect("No discord.json file found");
-        let mut discord_str = String::new();
-        discord_file.read_to_string(&mut discord_str).unwrap();
+        if config.flag_sync_dry_run {
+            info!("Flag sync is in dry run mode, users.flags will not be changed");
+        }
 
-        let discord: Discord =
-            serde_json::from_str(&discord_str).expect("discord.json was not well-formatted");
+        let dry_run = config
+            .flag_sync_dry_run
+            .then(|| Arc::new(flags::DryRunLog::default()));
+
+        let reporter = Arc::new(ErrorReporter::new(
+            Arc::new(serenity::http::Http::new(&tokens.token_main)),
+            live.clone(),
+            Duration::from_secs(config.error_report_window),
+        ));
+
+        let metrics = Arc::new(Metrics::default());
+        let flag_sync = Arc::new(flags::FlagSync::new(
+            pool.clone(),
+            live.clone(),
+            reporter.clone(),
+            metrics.clone(),
+            dry_run.clone(),
+        ));
 
         // Login main, server and squirrelflight using serenity
 
+        let main_state = Arc::new(BotState::default());
+        let server_state = Arc::new(BotState::default());
+
+        let max_backoff = Duration::from_secs(config.restart_backoff_max);
+
         // Main client
-        let mut main_cli = Client::builder(
-            &tokens.token_main.clone(),
+        let main = supervisor::start(
+            "main",
+            tokens.token_main.clone(),
             GatewayIntents::GUILDS
                 | GatewayIntents::GUILD_MESSAGES
                 | GatewayIntents::GUILD_MEMBERS
                 | GatewayIntents::GUILD_PRESENCES,
+            MainHandler {
+                state: main_state.clone(),
+                flags: flag_sync.clone(),
+            },
+            main_state,
+            if config.main_autoshard {
+                Sharding::Auto
+            } else {
+                Sharding::Single
+            },
+            max_backoff,
         )
-        .event_handler(MainHandler {
-            normal_roles: normal_roles.clone(),
-            pool: pool.clone(),
-            discord: discord.clone(),
-        })
-        .await
-        .unwrap();
-
-        let main_cache = main_cli.cache_and_http.clone();
+        .await;
 
-        task::spawn(async move { main_cli.start().await });
+        if config.reconcile_interval > 0 {
+            let period = Duration::from_secs(config.reconcile_interval);
+            task::spawn(flag_sync.reconcile_every(period, main.http()));
+        }
 
         // Server client
-        let mut server_cli = Client::builder(
-            &tokens.token_squirrelflight.clone(),
+        let servers = supervisor::start(
+            "servers",
+            tokens.token_squirrelflight.clone(),
             GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES,
+            ServerHandler {
+                state: server_state.clone(),
+            },
+            server_state,
+            Sharding::Single,
+            max_backoff,
         )
-        .await
-        .unwrap();
+        .await;
 
         // Fetch bot 1
-        let fetch_bot_1_cli = serenity::http::Http::new(&tokens.token_fetch_bot_1.clone());
-
-        let server_cache = server_cli.cache_and_http.clone();
-
-        task::spawn(async move {
-            let res = server_cli.start().await;
-            if res.is_err() {
-                error!("{}", res.err().unwrap());
-            }
-        });
+        let fetch_bot_1_cli = Arc::new(serenity::http::Http::new(&tokens.token_fetch_bot_1));
 
         Database {
             redis: cfg.create_pool(Some(Runtime::Tokio1)).unwrap(),
             clis: Clients {
-                main: main_cache,
-                servers: server_cache,
+                main,
+                servers,
                 fetcher: fetch_bot_1_cli,
             },
-            staff_roles,
             pool,
-            staff_roles_cache,
-            normal_roles,
-            normal_roles_cache,
-            discord,
+            live,
+            secret: tokens.baypaw_secret,
+            config: config.clone(),
+            reporter,
+            metrics,
+            dry_run,
         }
     }
 
-    pub async fn get_user_perms(&self, id: u64) -> &StaffRole {
-        let mut perms = self.staff_roles.get("user").unwrap();
+    /// Stops both bots and closes the Postgres and Redis pools. Only call this once
+    /// nothing else needs them
+    pub async fn shutdown(&self) {
+        self.clis.main.shutdown().await;
+        self.clis.servers.shutdown().await;
 
-        if let Some(member) = self
-            .clis
-            .main
-            .cache
-            .member(self.discord.servers.main, UserId(id))
-        {
-            /*
-             * Iterate over every role the member has and check if its in staff_role_cache or not
-             * This is also more optimized than looping over all of staff_roles as we only loop
-             * over all roles once
-             */
-            for role in member.roles {
-                if let Some(possible_perm) = self.staff_roles_cache.get(&role.0) {
-                    let possible = self.staff_roles.get(possible_perm).unwrap();
-                    if possible.perm > perms.perm {
-                        perms = possible;
-                    }
-                }
+        self.pool.close().await;
+        self.redis.close();
+
+        info!("Closed database connections");
+    }
+
+    pub async fn get_user_perms(&self, id: u64) -> StaffRole {
+        let live = self.live.load();
+
+        let roles = self.main_member_roles(&live, id).unwrap_or_default();
+
+        highest_staff_role(&live, &roles).clone()
+    }
+
+    pub async fn get_normal_roles(&self, id: u64) -> NormalRole {
+        let live = self.live.load();
+
+        let roles = self.main_member_roles(&live, id).unwrap_or_default();
+
+        highest_normal_role(&live, &roles).clone()
+    }
+
+    /// Returns every staff and normal role a user has instead of only the highest,
+    /// highest first
+    pub async fn get_all_roles(&self, id: u64) -> AllRoles {
+        let live = self.live.load();
+
+        let roles = self.main_member_roles(&live, id).unwrap_or_default();
+
+        let mut all = AllRoles {
+            staff_roles: Vec::new(),
+            normal_roles: Vec::new(),
+        };
+
+        for role in &roles {
+            if let Some(key) = live.staff_roles_cache.get(&role.0) {
+                all.staff_roles
+                    .push(live.staff_roles.get(key).unwrap().clone());
+            }
+
+            if let Some(key) = live.normal_roles_cache.get(&role.0) {
+                all.normal_roles
+                    .push(live.normal_roles.get(key).unwrap().clone());
             }
         }
 
-        perms
+        all.staff_roles
+            .sort_by(|a, b| b.perm.partial_cmp(&a.perm).unwrap_or(Ordering::Equal));
+        all.normal_roles.sort_by(|a, b| b.flag.cmp(&a.flag));
+
+        all
     }
 
-    pub async fn get_normal_roles(&self, id: u64) -> &NormalRole {
-        let mut flags = self.normal_roles.get("user").unwrap();
+    /// get_user_perms for many users at once
+    pub async fn get_user_perms_many(&self, ids: &[u64]) -> BatchRoles<StaffRole> {
+        let live = self.live.load();
 
-        if let Some(member) = self
-            .clis
+        self.batch_roles(&live, ids, |roles| highest_staff_role(&live, roles).clone())
+    }
+
+    /// get_normal_roles for many users at once
+    pub async fn get_normal_roles_many(&self, ids: &[u64]) -> BatchRoles<NormalRole> {
+        let live = self.live.load();
+
+        self.batch_roles(&live, ids, |roles| {
+            highest_normal_role(&live, roles).clone()
+        })
+    }
+
+    fn batch_roles<T>(
+        &self,
+        live: &LiveConfig,
+        ids: &[u64],
+        role_for: impl Fn(&[RoleId]) -> T,
+    ) -> BatchRoles<T> {
+        let mut batch = BatchRoles {
+            results: HashMap::new(),
+            not_cached: Vec::new(),
+        };
+
+        for id in ids {
+            let roles = match self.main_member_roles(live, *id) {
+                Some(roles) => roles,
+                None => {
+                    batch.not_cached.push(*id);
+                    Vec::new()
+                }
+            };
+
+            batch.results.insert(*id, role_for(&roles));
+        }
+
+        batch
+    }
+
+    /// Returns the roles a user has on the main server or None if they aren't in
+    /// our cache
+    fn main_member_roles(&self, live: &LiveConfig, id: u64) -> Option<Vec<RoleId>> {
+        self.clis
             .main
-            .cache
-            .member(self.discord.servers.main, UserId(id))
-        {
-            /*
-             * Iterate over every role the member has and check if its in normal_role_cache or not
-             * This is also more optimized than looping over all of normal_roles as we only loop
-             * over all roles once
-             */
-            for role in member.roles {
-                if let Some(possible_flag) = self.normal_roles_cache.get(&role.0) {
-                    let possible = self.normal_roles.get(possible_flag).unwrap();
-
-                    if possible.flag > flags.flag {
-                        flags = possible;
-                    }
+            .cache()
+            .member_field(live.discord.servers.main, UserId(id), |member| {
+                member.roles.clone()
+            })
+    }
+
+    pub async fn get_flag_audit(
+        &self,
+        user_id: u64,
+    ) -> Result<Vec<flags::FlagAuditEntry>, sqlx::Error> {
+        flags::flag_history(&self.pool, user_id).await
+    }
+
+    pub async fn getch(&self, id: u64) -> Result<Option<IUser>, serenity::Error> {
+        if let Some(user) = self.getch_cached(id).await {
+            return Ok(Some(user));
+        }
+
+        self.getch_fetch(id).await
+    }
+
+    /// Looks up many users at once. Everything we have cached is answered in one pass
+    /// before the rest are fetched with at most getch_batch_concurrency requests at a time
+    pub async fn getch_many(
+        &self,
+        mut ids: Vec<u64>,
+    ) -> HashMap<u64, Result<Option<IUser>, serenity::Error>> {
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut users = HashMap::new();
+        let mut missing = Vec::new();
+
+        for id in ids {
+            match self.getch_cached(id).await {
+                Some(user) => {
+                    users.insert(id, Ok(Some(user)));
                 }
+                None => missing.push(id),
             }
         }
 
-        flags
+        debug!(
+            "{} users found in cache, fetching {} more",
+            users.len(),
+            missing.len()
+        );
+
+        let fetched: Vec<(u64, Result<Option<IUser>, serenity::Error>)> = stream::iter(missing)
+            .map(|id| async move { (id, self.getch_fetch(id).await) })
+            .buffer_unordered(self.config.getch_batch_concurrency.max(1))
+            .collect()
+            .await;
+
+        users.extend(fetched);
+
+        users
     }
 
-    pub async fn getch(&self, id: u64) -> Option<IUser> {
+    /// Looks up a user in the main and server caches without making any requests
+    pub async fn getch_cached(&self, id: u64) -> Option<IUser> {
         // First check the main_cli
+        let main_cache = self.clis.main.cache();
+
         debug!(
             "Have {count} cached users in main cli",
-            count = self.clis.main.cache.user_count(),
+            count = main_cache.user_count(),
         );
 
         let user_id = UserId(id);
 
-        let cached_data = user_id.to_user_cached(&self.clis.main.cache).await;
-
-        if cached_data.is_some() {
-            let cached_data = cached_data.unwrap();
-
-            for id in self.clis.main.cache.guilds() {
-                let p_opt = self
-                    .clis
-                    .main
-                    .cache
-                    .guild_field(id, |guild| guild.presences.clone());
+        if let Some(cached_data) = user_id.to_user_cached(&main_cache).await {
+            for id in main_cache.guilds() {
+                let p_opt = main_cache.guild_field(id, |guild| guild.presences.clone());
                 if let Some(p) = p_opt {
                     let status = p.get(&user_id);
 
                     if let Some(status) = status {
+                        self.metrics.getch(GetchTier::MainCache);
+
                         return Some(IUser {
                             user: cached_data,
                             status: match status.status {
@@ -464,6 +619,8 @@ impl Database {
                 }
             }
 
+            self.metrics.getch(GetchTier::MainCache);
+
             return Some(IUser {
                 user: cached_data,
                 status: Status::Unknown,
@@ -471,32 +628,117 @@ impl Database {
         }
 
         // Then check the server_cli
+        let servers_cache = self.clis.servers.cache();
+
         debug!(
             "Have {count} cached users in server cli",
-            count = self.clis.servers.cache.user_count(),
+            count = servers_cache.user_count(),
         );
 
-        let cached_data = user_id.to_user_cached(&self.clis.servers.cache).await;
+        let cached_data = user_id.to_user_cached(&servers_cache).await?;
+
+        self.metrics.getch(GetchTier::ServersCache);
 
         // No presence intent so....
-        if cached_data.is_some() {
-            return Some(IUser {
-                user: cached_data.unwrap(),
-                status: Status::Unknown,
+        Some(IUser {
+            user: cached_data,
+            status: Status::Unknown,
+        })
+    }
+
+    /// Looks up a user in redis and then asks Discord using fetch_bot_1. Returns
+    /// Ok(None) if Discord says the user does not exist
+    pub async fn getch_fetch(&self, id: u64) -> Result<Option<IUser>, serenity::Error> {
+        // First check if we fetched them recently
+        if let Some(cached_data) = self.getch_redis(id).await {
+            self.metrics.getch(match cached_data {
+                Some(_) => GetchTier::Redis,
+                None => GetchTier::NotFound,
             });
+
+            return Ok(cached_data.map(|user| IUser {
+                user,
+                status: Status::Unknown,
+            }));
         }
 
         // All failed, lets move to fetch_bot_1
         let fetched = self.clis.fetcher.get_user(id).await;
 
-        if fetched.is_err() {
-            error!("{:?}", fetched.unwrap_err());
-            return None;
+        match fetched {
+            Ok(user) => {
+                self.metrics.getch(GetchTier::Fetch);
+                self.cache_getch(id, Some(&user)).await;
+
+                Ok(Some(IUser {
+                    user,
+                    status: Status::Unknown,
+                }))
+            }
+            Err(serenity::Error::Http(err)) if is_not_found(&err) => {
+                debug!("User {} does not exist", id);
+                self.metrics.getch(GetchTier::NotFound);
+                self.cache_getch(id, None).await;
+                Ok(None)
+            }
+            Err(err) => {
+                self.metrics.getch(GetchTier::Error);
+                self.reporter
+                    .report(ErrorReport::new("Error fetching user", &err).field("User", id));
+                Err(err)
+            }
+        }
+    }
+
+    /// Looks up a user fetch_bot_1 fetched before. Returns Some(None) if we already
+    /// know the user does not exist
+    async fn getch_redis(&self, id: u64) -> Option<Option<User>> {
+        let mut conn = match self.redis.get().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("Could not get redis connection: {}", err);
+                return None;
+            }
+        };
+
+        let cached: Option<String> = match conn.get(getch_key(id)).await {
+            Ok(cached) => cached,
+            Err(err) => {
+                error!("Could not read user {} from redis: {}", id, err);
+                return None;
+            }
+        };
+
+        serde_json::from_str(&cached?).ok()
+    }
+
+    /// Caches a fetched user (or the fact that they don't exist) in redis so we
+    /// don't have to ask Discord again
+    async fn cache_getch(&self, id: u64, user: Option<&User>) {
+        let ttl = match user {
+            Some(_) => self.config.getch_cache_ttl,
+            None => self.config.getch_negative_ttl,
+        };
+
+        if ttl == 0 {
+            return;
+        }
+
+        let mut conn = match self.redis.get().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("Could not get redis connection: {}", err);
+                return;
+            }
+        };
+
+        let res: Result<(), _> = conn
+            .set_ex(getch_key(id), serde_json::to_string(&user).unwrap(), ttl)
+            .await;
+
+        if let Err(err) = res {
+            error!("Could not cache user {} in redis: {}", id, err);
         }
-        Some(IUser {
-            user: fetched.unwrap(),
-            status: Status::Unknown,
-        })
     }
 
     pub async fn guild_invite(&self, cid: u64, uid: u64) -> Option<String> {
@@ -512,7 +754,7 @@ impl Database {
         let chan = self
             .clis
             .servers
-            .http
+            .http()
             .create_invite(
                 cid,
                 &map,
@@ -520,6 +762,13 @@ impl Database {
             )
             .await;
 
-        chan.ok().map(|c| c.url())
+        match chan {
+            Ok(invite) => Some(invite.url()),
+            Err(err) => {
+                debug!("Could not create invite in {}: {:?}", cid, err);
+                self.metrics.invite_failed();
+                None
+            }
+        }
     }
 }
@@ -0,0 +1,164 @@
+// Errors returned by the IPC endpoints
+//
+// Every error is sent as {"code": ..., "message": ...} where code is a short
+// snake_case string that clients can branch on. Errors from Discord also carry
+// Discord's own error code as discord_code
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use serenity::http::HttpError;
+use std::fmt;
+
+// Discord JSON error codes we tell apart
+const UNKNOWN_CHANNEL: isize = 10003;
+const UNKNOWN_GUILD: isize = 10004;
+const UNKNOWN_MESSAGE: isize = 10008;
+const UNKNOWN_USER: isize = 10013;
+const MISSING_ACCESS: isize = 50001;
+const MISSING_PERMISSIONS: isize = 50013;
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    discord_code: Option<isize>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discord_code: Option<isize>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            discord_code: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized")
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Maps an error response from Discord to what we tell our caller
+    fn from_discord(status: u16, code: isize, message: String) -> Self {
+        let (status, api_code) = match (status, code) {
+            (_, MISSING_PERMISSIONS) | (_, MISSING_ACCESS) | (403, _) => {
+                (StatusCode::FORBIDDEN, "missing_permissions")
+            }
+            (_, UNKNOWN_CHANNEL) => (StatusCode::NOT_FOUND, "unknown_channel"),
+            (_, UNKNOWN_GUILD) => (StatusCode::NOT_FOUND, "unknown_guild"),
+            (_, UNKNOWN_MESSAGE) => (StatusCode::NOT_FOUND, "unknown_message"),
+            (_, UNKNOWN_USER) => (StatusCode::NOT_FOUND, "unknown_user"),
+            (404, _) => (StatusCode::NOT_FOUND, "not_found"),
+            (429, _) => (StatusCode::TOO_MANY_REQUESTS, "rate_limited"),
+            (400, _) => (StatusCode::BAD_REQUEST, "invalid_request"),
+            // Anything else is Discord's problem, not the caller's
+            _ => (StatusCode::BAD_GATEWAY, "discord_error"),
+        };
+
+        ApiError {
+            status,
+            code: api_code,
+            message,
+            discord_code: Some(code),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(ErrorBody {
+            code: self.code,
+            message: &self.message,
+            discord_code: self.discord_code,
+        })
+    }
+}
+
+impl From<serenity::Error> for ApiError {
+    fn from(err: serenity::Error) -> Self {
+        match err {
+            serenity::Error::Http(err) => match *err {
+                HttpError::UnsuccessfulRequest(resp) => ApiError::from_discord(
+                    resp.status_code.as_u16(),
+                    resp.error.code,
+                    resp.error.message,
+                ),
+                HttpError::Request(err) => ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "discord_unavailable",
+                    err.to_string(),
+                ),
+                err => ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal_error",
+                    err.to_string(),
+                ),
+            },
+            // We checked what we were about to send against Discord's limits
+            serenity::Error::Model(err) => ApiError::bad_request(err.to_string()),
+            serenity::Error::ExceededLimit(..) | serenity::Error::NotInRange(..) => {
+                ApiError::bad_request(err.to_string())
+            }
+            err => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                err.to_string(),
+            ),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => ApiError::not_found("not_found", "Not found"),
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "database_unavailable",
+                    err.to_string(),
+                )
+            }
+            err => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database_error",
+                err.to_string(),
+            ),
+        }
+    }
+}
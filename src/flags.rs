@@ -0,0 +1,767 @@
+// Keeps users.flags in sync with the roles users have on the main server
+//
+// Every flag in roles.json is "managed" by us. A user should have a managed
+// flag if and only if they have a role mapped to it, flags set by the site
+// itself are left alone. The one exception is roles with revoke_on_leave off,
+// whose flags users keep after leaving and after rejoining until they get the
+// role again. These are "retained" flags, kept in baypaw_retained_flags
+use crate::config::LiveConfig;
+use crate::metrics::Metrics;
+use crate::reporter::{ErrorReport, ErrorReporter};
+use arc_swap::ArcSwap;
+use futures::StreamExt;
+use log::{debug, info};
+use serde::Serialize;
+use serenity::http::Http;
+use serenity::model::id::{RoleId, UserId};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::{Postgres, Transaction};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{interval_at, Instant};
+
+#[derive(Debug)]
+pub enum SyncError {
+    Discord(serenity::Error),
+    Postgres(sqlx::Error),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Discord(err) => write!(f, "Discord error: {}", err),
+            SyncError::Postgres(err) => write!(f, "Postgres error: {}", err),
+        }
+    }
+}
+
+impl From<serenity::Error> for SyncError {
+    fn from(err: serenity::Error) -> Self {
+        SyncError::Discord(err)
+    }
+}
+
+impl From<sqlx::Error> for SyncError {
+    fn from(err: sqlx::Error) -> Self {
+        SyncError::Postgres(err)
+    }
+}
+
+/// Every flag that is controlled by a role in roles.json
+pub fn managed_flags(live: &LiveConfig) -> Vec<i32> {
+    let mut flags: Vec<i32> = live.normal_roles.values().map(|role| role.flag).collect();
+    flags.sort_unstable();
+    flags.dedup();
+    flags
+}
+
+/// The managed flags a member with these roles should have
+pub fn expected_flags(live: &LiveConfig, roles: &[RoleId]) -> Vec<i32> {
+    let mut flags: Vec<i32> = roles
+        .iter()
+        .filter_map(|role| live.normal_roles_cache.get(&role.0))
+        .map(|key| live.normal_roles.get(key).unwrap().flag)
+        .collect();
+    flags.sort_unstable();
+    flags.dedup();
+    flags
+}
+
+/// Managed flags users keep when they leave the main server
+pub fn kept_on_leave(live: &LiveConfig) -> Vec<i32> {
+    let mut flags: Vec<i32> = live
+        .normal_roles
+        .values()
+        .filter(|role| !role.revoke_on_leave)
+        .map(|role| role.flag)
+        .collect();
+    flags.sort_unstable();
+    flags.dedup();
+    flags
+}
+
+/// Works out what a user's flags should be given their current and retained
+/// flags and their roles on the main server, or None if they aren't on it
+pub fn target_flags(
+    live: &LiveConfig,
+    old: &[i32],
+    roles: Option<&[RoleId]>,
+    retained: &[i32],
+) -> Vec<i32> {
+    let kept = kept_on_leave(live);
+
+    // Users who left keep all their kept flags, members only the ones they left with
+    let mut expected: Vec<i32> = old
+        .iter()
+        .copied()
+        .filter(|flag| kept.contains(flag) && (roles.is_none() || retained.contains(flag)))
+        .collect();
+
+    if let Some(roles) = roles {
+        expected.extend(expected_flags(live, roles));
+    }
+
+    apply(old, &managed_flags(live), &expected)
+}
+
+/// Works out a user's retained flags once they have their new flags. Leaving
+/// retains every kept flag they have and getting a role back makes its flag an
+/// ordinary one again, so losing the role later takes the flag away
+pub fn next_retained(
+    live: &LiveConfig,
+    new: &[i32],
+    roles: Option<&[RoleId]>,
+    retained: &[i32],
+) -> Vec<i32> {
+    let kept = kept_on_leave(live);
+
+    match roles {
+        None => kept.into_iter().filter(|flag| new.contains(flag)).collect(),
+        Some(roles) => {
+            let expected = expected_flags(live, roles);
+
+            retained
+                .iter()
+                .copied()
+                .filter(|flag| kept.contains(flag) && new.contains(flag))
+                .filter(|flag| !expected.contains(flag))
+                .collect()
+        }
+    }
+}
+
+/// Replaces the managed flags in old with expected, keeping the order of any
+/// flags that stay
+pub fn apply(old: &[i32], managed: &[i32], expected: &[i32]) -> Vec<i32> {
+    let mut new: Vec<i32> = old
+        .iter()
+        .copied()
+        .filter(|flag| !managed.contains(flag) || expected.contains(flag))
+        .collect();
+
+    for flag in expected {
+        if !new.contains(flag) {
+            new.push(*flag);
+        }
+    }
+
+    new
+}
+
+/// Most users reconcile locks and updates in one transaction
+const RECONCILE_CHUNK: usize = 500;
+
+/// Walks every member of the main server and fixes the flags of anyone whose
+/// flags don't match their roles, including users who have left. Returns the
+/// number of users that were (or in dry run mode would have been) updated
+pub async fn reconcile(
+    http: Arc<Http>,
+    pool: &sqlx::PgPool,
+    live: &LiveConfig,
+    dry_run: Option<&DryRunLog>,
+) -> Result<usize, SyncError> {
+    let mut members = HashMap::new();
+
+    let mut members_iter = live.discord.servers.main.members_iter(http).boxed();
+    while let Some(member) = members_iter.next().await {
+        let member = member?;
+        members.insert(member.user.id.0 as i64, member.roles);
+    }
+
+    debug!("Reconciling flags for {} members", members.len());
+
+    let managed = managed_flags(live);
+    let ids: Vec<i64> = members.keys().copied().collect();
+
+    // Anyone who isn't a member anymore but still has a managed flag also needs fixing
+    let candidates: Vec<i64> = sqlx::query!(
+        "SELECT user_id FROM users WHERE user_id = ANY($1) OR flags && $2 ORDER BY user_id",
+        &ids[..],
+        &managed[..]
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| row.user_id)
+    .collect();
+
+    let mut updated = 0;
+
+    for chunk in candidates.chunks(RECONCILE_CHUNK) {
+        updated += reconcile_chunk(pool, live, &members, chunk, dry_run).await?;
+    }
+
+    Ok(updated)
+}
+
+/// Fixes the flags of a chunk of users in one transaction. The rows are read
+/// again under a lock so a member event syncing one of them at the same time
+/// can't have its change overwritten
+async fn reconcile_chunk(
+    pool: &sqlx::PgPool,
+    live: &LiveConfig,
+    members: &HashMap<i64, Vec<RoleId>>,
+    user_ids: &[i64],
+    dry_run: Option<&DryRunLog>,
+) -> Result<usize, SyncError> {
+    let mut tx = pool.begin().await?;
+
+    let rows = sqlx::query!(
+        "SELECT user_id, flags FROM users WHERE user_id = ANY($1) ORDER BY user_id FOR UPDATE",
+        user_ids
+    )
+    .fetch_all(&mut tx)
+    .await?;
+
+    let retained = retained_flags(&mut tx, user_ids).await?;
+    let mut updated = 0;
+
+    for row in rows {
+        let roles = members.get(&row.user_id).map(|roles| &roles[..]);
+        let retained = retained
+            .get(&row.user_id)
+            .map_or(&[][..], |flags| &flags[..]);
+        let new = target_flags(live, &row.flags, roles, retained);
+        let new_retained = next_retained(live, &new, roles, retained);
+
+        if dry_run.is_none() && new_retained != retained {
+            set_retained(&mut tx, row.user_id, &new_retained).await?;
+        }
+
+        if new == row.flags {
+            continue;
+        }
+
+        updated += 1;
+
+        if let Some(dry_run) = dry_run {
+            dry_run.record(row.user_id, &row.flags, &new, SyncSource::Reconcile);
+            continue;
+        }
+
+        debug!(
+            "Fixing flags of {}: {:?} -> {:?}",
+            row.user_id, row.flags, new
+        );
+
+        sqlx::query!(
+            "UPDATE users SET flags = $1 WHERE user_id = $2",
+            &new[..],
+            row.user_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        record_changes(
+            &mut tx,
+            live,
+            row.user_id,
+            &row.flags,
+            &new,
+            roles,
+            SyncSource::Reconcile,
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(updated)
+}
+
+/// What made us change a user's flags, recorded in the audit log
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncSource {
+    MemberUpdate,
+    MemberJoin,
+    MemberLeave,
+    Reconcile,
+}
+
+impl SyncSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncSource::MemberUpdate => "member_update",
+            SyncSource::MemberJoin => "member_join",
+            SyncSource::MemberLeave => "member_leave",
+            SyncSource::Reconcile => "reconcile",
+        }
+    }
+}
+
+/// Most audit entries flag_history returns
+const AUDIT_HISTORY_LIMIT: i64 = 100;
+
+/// An entry in baypaw_flag_audit, the audit log of every flag change we make.
+/// The table is in sql/baypaw.sql rather than the site's schema, which is why it
+/// is queried without the query! macros
+#[derive(Serialize, sqlx::FromRow)]
+pub struct FlagAuditEntry {
+    pub flag: i32,
+    /// Either add or remove
+    pub action: String,
+    /// The role that grants the flag
+    pub role_id: Option<i64>,
+    pub source: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Returns the latest flag changes of a user, newest first
+pub async fn flag_history(
+    pool: &sqlx::PgPool,
+    user_id: u64,
+) -> Result<Vec<FlagAuditEntry>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT flag, action, role_id, source, created_at FROM baypaw_flag_audit
+        WHERE user_id = $1 ORDER BY created_at DESC, id DESC LIMIT $2",
+    )
+    .bind(user_id as i64)
+    .bind(AUDIT_HISTORY_LIMIT)
+    .fetch_all(pool)
+    .await
+}
+
+/// The role that grants flag, preferring one the member actually has
+fn source_role(live: &LiveConfig, flag: i32, roles: Option<&[RoleId]>) -> Option<i64> {
+    let candidates: Vec<u64> = live
+        .normal_roles
+        .values()
+        .filter(|role| role.flag == flag)
+        .map(|role| role.id)
+        .collect();
+
+    candidates
+        .iter()
+        .find(|id| roles.is_some_and(|roles| roles.contains(&RoleId(**id))))
+        .or_else(|| candidates.first())
+        .map(|id| *id as i64)
+}
+
+/// Writes every flag that differs between old and new to the audit log
+async fn record_changes(
+    tx: &mut Transaction<'_, Postgres>,
+    live: &LiveConfig,
+    user_id: i64,
+    old: &[i32],
+    new: &[i32],
+    roles: Option<&[RoleId]>,
+    source: SyncSource,
+) -> Result<(), sqlx::Error> {
+    let added = new
+        .iter()
+        .filter(|flag| !old.contains(flag))
+        .map(|f| (*f, "add"));
+    let removed = old
+        .iter()
+        .filter(|flag| !new.contains(flag))
+        .map(|f| (*f, "remove"));
+
+    for (flag, action) in added.chain(removed) {
+        sqlx::query(
+            "INSERT INTO baypaw_flag_audit (user_id, flag, action, role_id, source)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(user_id)
+        .bind(flag)
+        .bind(action)
+        .bind(source_role(live, flag, roles))
+        .bind(source.as_str())
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// The retained flags of each of user_ids, sorted
+async fn retained_flags(
+    tx: &mut Transaction<'_, Postgres>,
+    user_ids: &[i64],
+) -> Result<HashMap<i64, Vec<i32>>, sqlx::Error> {
+    let rows: Vec<(i64, i32)> = sqlx::query_as(
+        "SELECT user_id, flag FROM baypaw_retained_flags WHERE user_id = ANY($1) ORDER BY flag",
+    )
+    .bind(user_ids)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut retained: HashMap<i64, Vec<i32>> = HashMap::new();
+
+    for (user_id, flag) in rows {
+        retained.entry(user_id).or_default().push(flag);
+    }
+
+    Ok(retained)
+}
+
+/// Replaces the retained flags of a user
+async fn set_retained(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    flags: &[i32],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM baypaw_retained_flags WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO baypaw_retained_flags (user_id, flag) SELECT $1, UNNEST($2::INTEGER[])",
+    )
+    .bind(user_id)
+    .bind(flags)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Most planned changes DryRunLog keeps
+const DRY_RUN_LOG_SIZE: usize = 1000;
+
+/// A flag change we would have made if we weren't in dry run mode
+#[derive(Serialize, Clone)]
+pub struct PlannedChange {
+    pub user_id: String,
+    pub old: Vec<i32>,
+    pub new: Vec<i32>,
+    pub source: SyncSource,
+    pub planned_at: DateTime<Utc>,
+}
+
+/// In dry run mode flag syncs only log what they would change here instead of
+/// touching users.flags, so new roles.json mappings can be checked first
+#[derive(Default)]
+pub struct DryRunLog {
+    planned: Mutex<VecDeque<PlannedChange>>,
+}
+
+impl DryRunLog {
+    fn record(&self, user_id: i64, old: &[i32], new: &[i32], source: SyncSource) {
+        info!(
+            "[dry run] Would change flags of {}: {:?} -> {:?}",
+            user_id, old, new
+        );
+
+        let mut planned = self.planned.lock().unwrap();
+
+        if planned.len() == DRY_RUN_LOG_SIZE {
+            planned.pop_front();
+        }
+
+        planned.push_back(PlannedChange {
+            user_id: user_id.to_string(),
+            old: old.to_vec(),
+            new: new.to_vec(),
+            source,
+            planned_at: Utc::now(),
+        });
+    }
+
+    /// Returns the changes we would have made, newest first
+    pub fn planned(&self) -> Vec<PlannedChange> {
+        self.planned.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+/// What happened when syncing the flags of a single user
+#[derive(Debug)]
+pub enum SyncOutcome {
+    /// The user has never logged in to the site so has no flags to sync
+    NotRegistered,
+    Unchanged,
+    Updated {
+        old: Vec<i32>,
+        new: Vec<i32>,
+    },
+    /// We are in dry run mode and would have updated the flags, the change is in
+    /// the dry run log
+    WouldUpdate,
+}
+
+/// Brings a single user's flags in line with their roles on the main server (or
+/// None if they aren't on it). The row is locked while we work out the new flags
+/// so concurrent syncs of the same user can't race. Nothing is changed if
+/// dry_run is set
+pub async fn sync_member(
+    pool: &sqlx::PgPool,
+    live: &LiveConfig,
+    user_id: UserId,
+    roles: Option<&[RoleId]>,
+    source: SyncSource,
+    dry_run: Option<&DryRunLog>,
+) -> Result<SyncOutcome, SyncError> {
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query!(
+        "SELECT flags FROM users WHERE user_id = $1 FOR UPDATE",
+        user_id.0 as i64
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+
+    let old = match row {
+        Some(row) => row.flags,
+        None => return Ok(SyncOutcome::NotRegistered),
+    };
+
+    let retained = retained_flags(&mut tx, &[user_id.0 as i64])
+        .await?
+        .remove(&(user_id.0 as i64))
+        .unwrap_or_default();
+    let new = target_flags(live, &old, roles, &retained);
+
+    if let Some(dry_run) = dry_run {
+        if new == old {
+            return Ok(SyncOutcome::Unchanged);
+        }
+
+        dry_run.record(user_id.0 as i64, &old, &new, source);
+        return Ok(SyncOutcome::WouldUpdate);
+    }
+
+    let new_retained = next_retained(live, &new, roles, &retained);
+
+    if new_retained != retained {
+        set_retained(&mut tx, user_id.0 as i64, &new_retained).await?;
+    }
+
+    if new == old {
+        tx.commit().await?;
+        return Ok(SyncOutcome::Unchanged);
+    }
+
+    sqlx::query!(
+        "UPDATE users SET flags = $1 WHERE user_id = $2",
+        &new[..],
+        user_id.0 as i64
+    )
+    .execute(&mut tx)
+    .await?;
+
+    record_changes(&mut tx, live, user_id.0 as i64, &old, &new, roles, source).await?;
+
+    tx.commit().await?;
+
+    Ok(SyncOutcome::Updated { old, new })
+}
+
+/// Clears the running flag of a reconcile once it is done, even if it panicked
+struct RunningGuard<'a>(&'a AtomicBool);
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Everything flag syncs need, shared by the main bot's handler and the periodic
+/// reconcile
+pub struct FlagSync {
+    pub pool: sqlx::PgPool,
+    pub live: Arc<ArcSwap<LiveConfig>>,
+    pub reporter: Arc<ErrorReporter>,
+    pub metrics: Arc<Metrics>,
+    pub dry_run: Option<Arc<DryRunLog>>,
+    /// Set while a reconcile is running so only one runs at a time, ready fires
+    /// for every shard and on every restart
+    reconciling: AtomicBool,
+}
+
+impl FlagSync {
+    pub fn new(
+        pool: sqlx::PgPool,
+        live: Arc<ArcSwap<LiveConfig>>,
+        reporter: Arc<ErrorReporter>,
+        metrics: Arc<Metrics>,
+        dry_run: Option<Arc<DryRunLog>>,
+    ) -> Self {
+        FlagSync {
+            pool,
+            live,
+            reporter,
+            metrics,
+            dry_run,
+            reconciling: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs reconcile and logs the outcome, reporting any errors. Does nothing if
+    /// a reconcile is already running
+    pub async fn reconcile_and_log(&self, http: Arc<Http>) {
+        if self
+            .reconciling
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            debug!("Flag reconciliation is already running, skipping");
+            return;
+        }
+
+        let _running = RunningGuard(&self.reconciling);
+
+        let live = self.live.load_full();
+        let dry_run = self.dry_run.as_deref();
+
+        match reconcile(http, &self.pool, &live, dry_run).await {
+            Ok(updated) if dry_run.is_some() => {
+                info!(
+                    "[dry run] Flag reconciliation done, would update {} users",
+                    updated
+                )
+            }
+            Ok(updated) => info!("Flag reconciliation done, updated {} users", updated),
+            Err(err) => {
+                self.metrics.flag_sync_failed(SyncSource::Reconcile);
+                self.reporter.report(
+                    ErrorReport::new("Flag reconciliation failed", err)
+                        .field("Server", live.discord.servers.main),
+                );
+            }
+        }
+    }
+
+    /// Reconciles flags every period. ready also reconciles so the first run is
+    /// only after a full period
+    pub async fn reconcile_every(self: Arc<Self>, period: Duration, http: Arc<Http>) {
+        let mut ticks = interval_at(Instant::now() + period, period);
+
+        loop {
+            ticks.tick().await;
+
+            self.reconcile_and_log(http.clone()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Channels, Discord, NormalRole, Servers};
+    use serenity::model::id::GuildId;
+
+    /// A LiveConfig with only the given (role id, flag, revoke_on_leave) roles
+    fn live(roles: &[(u64, i32, bool)]) -> LiveConfig {
+        let normal_roles: HashMap<String, NormalRole> = roles
+            .iter()
+            .map(|(id, flag, revoke_on_leave)| {
+                let role = NormalRole {
+                    id: *id,
+                    flag: *flag,
+                    fname: format!("Role {}", id),
+                    revoke_on_leave: *revoke_on_leave,
+                };
+                (format!("role_{}", id), role)
+            })
+            .collect();
+
+        let normal_roles_cache = normal_roles
+            .iter()
+            .map(|(key, role)| (role.id, key.clone()))
+            .collect();
+
+        LiveConfig {
+            staff_roles: HashMap::new(),
+            normal_roles,
+            discord: Discord {
+                servers: Servers { main: GuildId(1) },
+                channels: Channels {
+                    site_errors_channel: "2".to_string(),
+                },
+            },
+            staff_roles_cache: HashMap::new(),
+            normal_roles_cache,
+        }
+    }
+
+    #[test]
+    fn apply_replaces_only_managed_flags() {
+        assert_eq!(apply(&[1, 5, 2], &[2, 3], &[3]), vec![1, 5, 3]);
+        assert_eq!(apply(&[1, 2], &[2, 3], &[2, 3]), vec![1, 2, 3]);
+        assert_eq!(apply(&[], &[2, 3], &[]), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn apply_does_not_duplicate_flags() {
+        assert_eq!(apply(&[3, 1], &[3], &[3]), vec![3, 1]);
+    }
+
+    #[test]
+    fn kept_on_leave_skips_revoked_roles() {
+        let live = live(&[
+            (10, 2, true),
+            (11, 3, false),
+            (12, 3, false),
+            (13, 4, false),
+        ]);
+
+        assert_eq!(kept_on_leave(&live), vec![3, 4]);
+    }
+
+    #[test]
+    fn target_flags_follows_roles() {
+        let live = live(&[(10, 2, true), (11, 3, true)]);
+
+        let new = target_flags(&live, &[1, 2], Some(&[RoleId(11), RoleId(99)]), &[]);
+
+        assert_eq!(new, vec![1, 3]);
+    }
+
+    #[test]
+    fn target_flags_on_leave_keeps_only_kept_flags() {
+        let live = live(&[(10, 2, true), (11, 3, false)]);
+
+        assert_eq!(target_flags(&live, &[1, 2, 3], None, &[]), vec![1, 3]);
+        // Leaving never grants a flag the user didn't have
+        assert_eq!(target_flags(&live, &[1], None, &[]), vec![1]);
+    }
+
+    #[test]
+    fn target_flags_on_rejoin_keeps_retained_flags() {
+        let live = live(&[(10, 2, true), (11, 3, false)]);
+
+        // Rejoined without any roles
+        assert_eq!(target_flags(&live, &[1, 2, 3], Some(&[]), &[3]), vec![1, 3]);
+        // Getting the role back doesn't add the flag twice
+        assert_eq!(
+            target_flags(&live, &[3], Some(&[RoleId(11)]), &[3]),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn target_flags_revokes_kept_flags_of_members_losing_the_role() {
+        let live = live(&[(10, 2, true), (11, 3, false)]);
+
+        assert_eq!(target_flags(&live, &[1, 3], Some(&[]), &[]), vec![1]);
+    }
+
+    #[test]
+    fn next_retained_tracks_leaving_and_getting_the_role_back() {
+        let live = live(&[(10, 2, true), (11, 3, false), (12, 4, false)]);
+
+        // Leaving retains the kept flags the user still has
+        assert_eq!(next_retained(&live, &[1, 3], None, &[]), vec![3]);
+        // Rejoining without the role keeps it retained
+        assert_eq!(next_retained(&live, &[1, 3], Some(&[]), &[3]), vec![3]);
+        // Getting the role back makes it an ordinary flag again
+        assert_eq!(
+            next_retained(&live, &[1, 3], Some(&[RoleId(11)]), &[3]),
+            Vec::<i32>::new()
+        );
+    }
+
+    #[test]
+    fn next_retained_drops_flags_no_longer_kept() {
+        let live = live(&[(11, 3, true)]);
+
+        assert_eq!(
+            next_retained(&live, &[3], Some(&[]), &[3]),
+            Vec::<i32>::new()
+        );
+    }
+}
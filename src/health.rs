@@ -0,0 +1,161 @@
+// Health checks behind /healthz and /readyz
+//
+// Baypaw is healthy when Postgres and Redis answer and both bots are running
+// with every shard connected. It is ready once both bots have also received ready
+use crate::database::Database;
+use deadpool_redis::redis;
+use serde::Serialize;
+use serenity::gateway::ConnectionStage;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// How long Postgres and Redis get to answer before we call them down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// State of one of our bots, shared with its event handler
+#[derive(Default)]
+pub struct BotState {
+    /// Set once the bot has received ready
+    pub ready: AtomicBool,
+    /// Cleared while the supervisor is waiting to restart the client
+    pub running: AtomicBool,
+    /// How many times the supervisor has restarted the client
+    pub restarts: AtomicU64,
+    /// Why the client last stopped
+    pub last_error: Mutex<Option<String>>,
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    pub ready: bool,
+    pub bots: HashMap<&'static str, BotReport>,
+    pub postgres: DependencyReport,
+    pub redis: DependencyReport,
+}
+
+#[derive(Serialize)]
+pub struct BotReport {
+    pub ready: bool,
+    pub running: bool,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+    /// Whether every shard is connected
+    pub connected: bool,
+    pub shards: Vec<ShardReport>,
+    pub cached_guilds: usize,
+    pub cached_users: usize,
+    /// Members of the main server in this bot's cache
+    pub main_server_members: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ShardReport {
+    pub id: u64,
+    pub stage: String,
+    pub latency_ms: Option<u128>,
+}
+
+#[derive(Serialize)]
+pub struct DependencyReport {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl DependencyReport {
+    async fn check<E: ToString>(check: impl Future<Output = Result<(), E>>) -> Self {
+        let error = match timeout(CHECK_TIMEOUT, check).await {
+            Ok(Ok(())) => None,
+            Ok(Err(err)) => Some(err.to_string()),
+            Err(_) => Some("Timed out".to_string()),
+        };
+
+        DependencyReport {
+            ok: error.is_none(),
+            error,
+        }
+    }
+}
+
+pub async fn check(database: &Database) -> HealthReport {
+    let main_server = database.live.load().discord.servers.main;
+
+    let mut bots = HashMap::new();
+
+    for bot in [&database.clis.main, &database.clis.servers] {
+        let cache = bot.cache();
+        let state = &bot.state;
+        let running = state.running.load(Ordering::Relaxed);
+
+        let (shards, connected) = {
+            let shard_manager = bot.shard_manager();
+            let manager = shard_manager.lock().await;
+            let runners = manager.runners.lock().await;
+
+            let shards: Vec<ShardReport> = runners
+                .iter()
+                .map(|(id, runner)| ShardReport {
+                    id: id.0,
+                    stage: runner.stage.to_string(),
+                    latency_ms: runner.latency.map(|latency| latency.as_millis()),
+                })
+                .collect();
+
+            let connected = running
+                && !runners.is_empty()
+                && runners
+                    .values()
+                    .all(|runner| runner.stage == ConnectionStage::Connected);
+
+            (shards, connected)
+        };
+
+        bots.insert(
+            bot.name,
+            BotReport {
+                ready: state.ready.load(Ordering::Relaxed),
+                running,
+                restarts: state.restarts.load(Ordering::Relaxed),
+                last_error: state.last_error.lock().unwrap().clone(),
+                connected,
+                shards,
+                cached_guilds: cache.guild_count(),
+                cached_users: cache.user_count(),
+                main_server_members: cache.guild_field(main_server, |guild| guild.members.len()),
+            },
+        );
+    }
+
+    let postgres = DependencyReport::check(async {
+        sqlx::query("SELECT 1")
+            .execute(&database.pool)
+            .await
+            .map(|_| ())
+    })
+    .await;
+
+    let redis = DependencyReport::check(async {
+        let mut conn = database.redis.get().await.map_err(|err| err.to_string())?;
+
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|err| err.to_string())
+    })
+    .await;
+
+    let healthy = postgres.ok && redis.ok && bots.values().all(|bot| bot.connected);
+    let ready = healthy && bots.values().all(|bot| bot.ready);
+
+    HealthReport {
+        healthy,
+        ready,
+        bots,
+        postgres,
+        redis,
+    }
+}
@@ -1,87 +1,498 @@
-// A *very* primitive and possibly insecure IPC implementation
-// that will also hold the admin bot (in serenity) soon
+// A *very* primitive IPC implementation that will also hold the admin
+// bot (in serenity) soon
 //
-// This should never be run without a firewall blocking all remote
-// requests to port 1234!
-use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
-use log::{debug, error};
+// Every request must be authenticated with the shared secret from
+// secrets.json but this should still be firewalled from remote requests
+// to port 1234!
+use actix_web::dev::Service;
+use actix_web::{delete, get, patch, post, web, App, HttpResponse, HttpServer};
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
+mod auth;
+mod config;
 mod database;
+mod error;
+mod flags;
+mod health;
+mod metrics;
+mod reporter;
+mod supervisor;
 use bristlefrost::models::User;
+use error::ApiError;
+use reporter::ErrorReport;
 use serde_json::json;
+use serenity::http::Http;
+use serenity::model::channel::Embed;
 use serenity::model::id::GuildId;
+// serenity 0.11.2 (the version in Cargo.lock) only has ActionRow here, later 0.11
+// releases move it to model::application::component
+use serenity::model::interactions::message_component::ActionRow;
+use std::collections::HashMap;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 
 #[get("/perms/{id}")]
-async fn user_perms(req: HttpRequest, id: web::Path<u64>) -> HttpResponse {
-    let data: &IpcAppData = req.app_data::<web::Data<IpcAppData>>().unwrap();
-
+async fn user_perms(data: web::Data<IpcAppData>, id: web::Path<u64>) -> HttpResponse {
     HttpResponse::Ok().json(data.database.get_user_perms(id.into_inner()).await)
 }
 
 #[get("/roles/{id}")]
-async fn normal_roles(req: HttpRequest, id: web::Path<u64>) -> HttpResponse {
-    let data: &IpcAppData = req.app_data::<web::Data<IpcAppData>>().unwrap();
-
+async fn normal_roles(data: web::Data<IpcAppData>, id: web::Path<u64>) -> HttpResponse {
     HttpResponse::Ok().json(data.database.get_normal_roles(id.into_inner()).await)
 }
 
+#[get("/roles/{id}/all")]
+async fn all_roles(data: web::Data<IpcAppData>, id: web::Path<u64>) -> HttpResponse {
+    HttpResponse::Ok().json(data.database.get_all_roles(id.into_inner()).await)
+}
+
+#[get("/audit/flags/{user_id}")]
+async fn flag_audit(
+    data: web::Data<IpcAppData>,
+    user_id: web::Path<u64>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user_id.into_inner();
+
+    match data.database.get_flag_audit(user_id).await {
+        Ok(entries) => Ok(HttpResponse::Ok().json(entries)),
+        Err(err) => {
+            data.database.reporter.report(
+                ErrorReport::new("Error getting flag audit log", &err).field("User", user_id),
+            );
+            Err(err.into())
+        }
+    }
+}
+
+#[get("/flags/dry-run")]
+async fn flag_dry_run(data: web::Data<IpcAppData>) -> HttpResponse {
+    let planned = match data.database.dry_run {
+        Some(ref dry_run) => dry_run.planned(),
+        None => Vec::new(),
+    };
+
+    HttpResponse::Ok().json(json!({
+        "enabled": data.database.dry_run.is_some(),
+        "planned": planned,
+    }))
+}
+
+#[get("/healthz")]
+async fn healthz(data: web::Data<IpcAppData>) -> HttpResponse {
+    let report = health::check(&data.database).await;
+
+    if report.healthy {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+#[get("/readyz")]
+async fn readyz(data: web::Data<IpcAppData>) -> HttpResponse {
+    let report = health::check(&data.database).await;
+
+    if report.ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+#[get("/metrics")]
+async fn prometheus_metrics(data: web::Data<IpcAppData>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(data.database.metrics.render())
+}
+
+#[post("/perms")]
+async fn user_perms_many(data: web::Data<IpcAppData>, ids: web::Json<Vec<u64>>) -> HttpResponse {
+    HttpResponse::Ok().json(data.database.get_user_perms_many(&ids).await)
+}
+
+#[post("/roles")]
+async fn normal_roles_many(data: web::Data<IpcAppData>, ids: web::Json<Vec<u64>>) -> HttpResponse {
+    HttpResponse::Ok().json(data.database.get_normal_roles_many(&ids).await)
+}
+
 #[get("/getch/{id}")]
-async fn getch(req: HttpRequest, id: web::Path<u64>) -> HttpResponse {
-    let data: &IpcAppData = req.app_data::<web::Data<IpcAppData>>().unwrap();
+async fn getch(data: web::Data<IpcAppData>, id: web::Path<u64>) -> Result<HttpResponse, ApiError> {
+    data.database
+        .getch(id.into_inner())
+        .await?
+        .map(|user| {
+            debug!("Found user {}", user.user);
+            HttpResponse::Ok().json(to_user(user))
+        })
+        .ok_or_else(|| ApiError::not_found("unknown_user", "User not found"))
+}
 
-    let user = data.database.getch(id.into_inner()).await;
+#[derive(Serialize)]
+#[serde(untagged)]
+enum GetchResult {
+    Found(User),
+    /// code is one of the error codes in the README
+    Error {
+        code: &'static str,
+        error: String,
+    },
+}
 
-    if user.is_some() {
-        let user = user.unwrap();
-        debug!("Found user {}", user.user);
+#[post("/getch")]
+async fn getch_many(
+    data: web::Data<IpcAppData>,
+    ids: web::Json<Vec<u64>>,
+) -> Result<HttpResponse, ApiError> {
+    let max = data.database.config.getch_batch_max;
 
-        let avatar = user.user.avatar_url().unwrap_or_else(|| "".to_string());
+    if ids.len() > max {
+        return Err(ApiError::bad_request(format!(
+            "At most {} users can be looked up at once",
+            max
+        )));
+    }
 
-        return HttpResponse::Ok().json(User {
-            username: user.user.name,
-            disc: user.user.discriminator.to_string(),
-            id: user.user.id.to_string(),
-            avatar,
-            status: user.status,
-            bot: user.user.bot,
-        });
+    let users: HashMap<u64, GetchResult> = data
+        .database
+        .getch_many(ids.into_inner())
+        .await
+        .into_iter()
+        .map(|(id, user)| {
+            let res = match user {
+                Ok(Some(user)) => GetchResult::Found(to_user(user)),
+                Ok(None) => GetchResult::Error {
+                    code: "unknown_user",
+                    error: "User not found".to_string(),
+                },
+                Err(err) => {
+                    let err = ApiError::from(err);
+
+                    GetchResult::Error {
+                        code: err.code(),
+                        error: err.message().to_string(),
+                    }
+                }
+            };
+            (id, res)
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+fn to_user(user: database::IUser) -> User {
+    let avatar = user.user.avatar_url().unwrap_or_else(|| "".to_string());
+
+    User {
+        username: user.user.name,
+        disc: user.user.discriminator.to_string(),
+        id: user.user.id.to_string(),
+        avatar,
+        status: user.status,
+        bot: user.user.bot,
     }
-    HttpResponse::NotFound().finish()
 }
 
+/// Most embeds Discord allows on one message
+const EMBEDS_LIMIT: usize = 10;
+
 #[derive(Serialize, Deserialize)]
 struct Message {
     pub channel_id: u64,
+    #[serde(flatten)]
+    pub body: MessageBody,
+}
+
+/// Which of our bots sends a message
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Sender {
+    Main,
+    /// Squirrelflight, for server listing guilds the main bot isn't in
+    Servers,
+    Fetcher,
+}
+
+fn default_sender() -> Sender {
+    Sender::Main
+}
+
+impl Sender {
+    fn http(self, clis: &database::Clients) -> Arc<Http> {
+        match self {
+            Sender::Main => clis.main.http(),
+            Sender::Servers => clis.servers.http(),
+            Sender::Fetcher => clis.fetcher.clone(),
+        }
+    }
+}
+
+/// Everything in a message apart from where it goes
+#[derive(Serialize, Deserialize)]
+struct MessageBody {
+    /// Messages can only be edited or deleted by the bot that sent them
+    #[serde(default = "default_sender")]
+    pub sender: Sender,
+    #[serde(default)]
     pub content: String,
-    pub embed: serenity::model::channel::Embed,
+    /// A single embed, sent before embeds. Kept for older callers
+    #[serde(default)]
+    pub embed: Option<Embed>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    /// Action rows of buttons or select menus
+    #[serde(default)]
+    pub components: Vec<ActionRow>,
+    /// Which mentions in content ping anyone. Discord's default of everything
+    /// mentioned is used if this isn't set
+    #[serde(default)]
+    pub allowed_mentions: Option<AllowedMentions>,
+    /// Id of a message in the same channel to reply to
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+    #[serde(default)]
+    pub tts: bool,
+    #[serde(default)]
     pub mention_roles: Vec<String>,
 }
 
+/// The parts of a message to change when editing. Anything left out is kept as
+/// it is on Discord
+#[derive(Serialize, Deserialize)]
+struct EditMessageBody {
+    /// Must be the bot that sent the message
+    #[serde(default = "default_sender")]
+    pub sender: Sender,
+    #[serde(default)]
+    pub content: Option<String>,
+    /// A single embed, sent before embeds. Kept for older callers
+    #[serde(default)]
+    pub embed: Option<Embed>,
+    /// Replaces every embed on the message, along with embed
+    #[serde(default)]
+    pub embeds: Option<Vec<Embed>>,
+    #[serde(default)]
+    pub components: Option<Vec<ActionRow>>,
+    #[serde(default)]
+    pub allowed_mentions: Option<AllowedMentions>,
+    #[serde(default)]
+    pub mention_roles: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MentionType {
+    Roles,
+    Users,
+    Everyone,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AllowedMentions {
+    /// Kinds of mentions that ping everyone mentioned
+    #[serde(default)]
+    pub parse: Vec<MentionType>,
+    #[serde(default)]
+    pub roles: Vec<u64>,
+    #[serde(default)]
+    pub users: Vec<u64>,
+    /// Whether the author of the message being replied to is pinged
+    #[serde(default)]
+    pub replied_user: bool,
+}
+
+/// Errors if there are more embeds than Discord allows on one message
+fn check_embeds(embeds: &[&Embed]) -> Result<(), ApiError> {
+    if embeds.len() > EMBEDS_LIMIT {
+        return Err(ApiError::bad_request(format!(
+            "At most {} embeds can be sent at once",
+            EMBEDS_LIMIT
+        )));
+    }
+
+    Ok(())
+}
+
+impl MessageBody {
+    /// The message as Discord expects it when sending
+    fn to_json(&self) -> Result<serde_json::Value, ApiError> {
+        let embeds: Vec<&Embed> = self.embed.iter().chain(&self.embeds).collect();
+
+        check_embeds(&embeds)?;
+
+        if self.content.is_empty() && embeds.is_empty() && self.components.is_empty() {
+            return Err(ApiError::bad_request(
+                "Messages need content, an embed or components",
+            ));
+        }
+
+        let mut payload = json!({
+            "content": self.content,
+            "embeds": embeds,
+            "components": self.components,
+            "mention_roles": self.mention_roles,
+            "tts": self.tts,
+        });
+
+        if let Some(ref allowed_mentions) = self.allowed_mentions {
+            payload["allowed_mentions"] = json!(allowed_mentions);
+        }
+
+        if let Some(reply_to) = self.reply_to {
+            payload["message_reference"] = json!({ "message_id": reply_to });
+        }
+
+        Ok(payload)
+    }
+}
+
+impl EditMessageBody {
+    /// The changes as Discord expects them, with only the fields the caller sent
+    fn to_json(&self) -> Result<serde_json::Value, ApiError> {
+        let mut payload = json!({});
+
+        if let Some(ref content) = self.content {
+            payload["content"] = json!(content);
+        }
+
+        if self.embed.is_some() || self.embeds.is_some() {
+            let embeds: Vec<&Embed> = self
+                .embed
+                .iter()
+                .chain(self.embeds.iter().flatten())
+                .collect();
+
+            check_embeds(&embeds)?;
+
+            payload["embeds"] = json!(embeds);
+        }
+
+        if let Some(ref components) = self.components {
+            payload["components"] = json!(components);
+        }
+
+        if let Some(ref allowed_mentions) = self.allowed_mentions {
+            payload["allowed_mentions"] = json!(allowed_mentions);
+        }
+
+        if let Some(ref mention_roles) = self.mention_roles {
+            payload["mention_roles"] = json!(mention_roles);
+        }
+
+        if payload.as_object().is_none_or(|fields| fields.is_empty()) {
+            return Err(ApiError::bad_request("Nothing to edit"));
+        }
+
+        Ok(payload)
+    }
+}
+
+/// Where a message ended up so it can be edited or deleted later
+#[derive(Serialize)]
+struct SentMessage {
+    id: u64,
+    channel_id: u64,
+}
+
+impl From<serenity::model::channel::Message> for SentMessage {
+    fn from(msg: serenity::model::channel::Message) -> Self {
+        SentMessage {
+            id: msg.id.0,
+            channel_id: msg.channel_id.0,
+        }
+    }
+}
+
 #[post("/messages")]
-async fn send_message(req: HttpRequest, msg: web::Json<Message>) -> HttpResponse {
-    let data: &IpcAppData = req.app_data::<web::Data<IpcAppData>>().unwrap();
+async fn send_message(
+    data: web::Data<IpcAppData>,
+    msg: web::Json<Message>,
+) -> Result<HttpResponse, ApiError> {
+    let res = msg
+        .body
+        .sender
+        .http(&data.database.clis)
+        .send_message(msg.channel_id, &msg.body.to_json()?)
+        .await;
 
-    let res = data
-        .database
-        .clis
-        .main
-        .http
-        .send_message(
-            msg.channel_id,
-            &json!({
-                "content": msg.content,
-                "embeds": vec![msg.embed.clone()],
-                "mention_roles": msg.mention_roles,
-            }),
-        )
+    match res {
+        Ok(sent) => Ok(HttpResponse::Ok().json(SentMessage::from(sent))),
+        Err(err) => {
+            data.database.reporter.report(
+                ErrorReport::new("Error sending message", &err)
+                    .field("Channel", msg.channel_id)
+                    .field("Sender", format!("{:?}", msg.body.sender)),
+            );
+            Err(err.into())
+        }
+    }
+}
+
+/// Changes the content, embeds or components of a message we sent. Only the fields
+/// in the body are changed, channel_id is taken from the path
+#[patch("/messages/{channel_id}/{message_id}")]
+async fn edit_message(
+    data: web::Data<IpcAppData>,
+    path: web::Path<(u64, u64)>,
+    body: web::Json<EditMessageBody>,
+) -> Result<HttpResponse, ApiError> {
+    let (channel_id, message_id) = path.into_inner();
+
+    let res = body
+        .sender
+        .http(&data.database.clis)
+        .edit_message(channel_id, message_id, &body.to_json()?)
         .await;
 
-    if res.is_err() {
-        error!("Error sending message: {:?}", res.err());
-        return HttpResponse::BadRequest().finish();
+    match res {
+        Ok(edited) => Ok(HttpResponse::Ok().json(SentMessage::from(edited))),
+        Err(err) => {
+            data.database.reporter.report(
+                ErrorReport::new("Error editing message", &err)
+                    .field("Channel", channel_id)
+                    .field("Message", message_id)
+                    .field("Sender", format!("{:?}", body.sender)),
+            );
+            Err(err.into())
+        }
     }
+}
 
-    HttpResponse::Ok().finish()
+#[derive(Deserialize)]
+struct DeleteMessageQuery {
+    #[serde(default = "default_sender")]
+    sender: Sender,
+}
+
+#[delete("/messages/{channel_id}/{message_id}")]
+async fn delete_message(
+    data: web::Data<IpcAppData>,
+    path: web::Path<(u64, u64)>,
+    query: web::Query<DeleteMessageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (channel_id, message_id) = path.into_inner();
+
+    let res = query
+        .sender
+        .http(&data.database.clis)
+        .delete_message(channel_id, message_id)
+        .await;
+
+    if let Err(err) = res {
+        data.database.reporter.report(
+            ErrorReport::new("Error deleting message", &err)
+                .field("Channel", channel_id)
+                .field("Message", message_id)
+                .field("Sender", format!("{:?}", query.sender)),
+        );
+        return Err(err.into());
+    }
+
+    Ok(HttpResponse::NoContent().finish())
 }
 
 /// Important: This API does not handle server privacy. This should be
@@ -101,19 +512,20 @@ struct GuildInviteData {
 }
 
 #[get("/guild-invite")]
-async fn guild_invite(req: HttpRequest, info: web::Query<GuildInviteQuery>) -> HttpResponse {
-    let data: &IpcAppData = req.app_data::<web::Data<IpcAppData>>().unwrap();
-
+async fn guild_invite(
+    data: web::Data<IpcAppData>,
+    info: web::Query<GuildInviteQuery>,
+) -> Result<HttpResponse, ApiError> {
     if info.cid != 0 {
         let invite_code = data.database.guild_invite(info.cid, info.uid).await;
 
         if let Some(url) = invite_code {
-            return HttpResponse::Ok().json(GuildInviteData { url, cid: info.cid });
+            return Ok(HttpResponse::Ok().json(GuildInviteData { url, cid: info.cid }));
         }
     }
 
     // First get channels from cache
-    let chan_cache = GuildId(info.gid).to_guild_cached(data.database.clis.servers.cache.clone());
+    let chan_cache = GuildId(info.gid).to_guild_cached(data.database.clis.servers.cache());
 
     if let Some(guild) = chan_cache {
         let channels = guild.channels;
@@ -121,34 +533,53 @@ async fn guild_invite(req: HttpRequest, info: web::Query<GuildInviteQuery>) -> H
             let invite_code = data.database.guild_invite(channel.0, info.uid).await;
 
             if let Some(url) = invite_code {
-                return HttpResponse::Ok().json(GuildInviteData {
+                return Ok(HttpResponse::Ok().json(GuildInviteData {
                     url,
                     cid: channel.0,
-                });
+                }));
             }
         }
     } else {
         let res = GuildId(info.gid)
-            .channels(data.database.clis.servers.http.clone())
+            .channels(data.database.clis.servers.http())
             .await;
-        if let Err(err) = res {
+        let channels = res.map_err(|err| {
             error!("Error getting channels: {:?}", err);
-            return HttpResponse::BadRequest().finish();
-        }
-        let channels = res.unwrap();
+            ApiError::from(err)
+        })?;
         for channel in channels.keys() {
             let invite_code = data.database.guild_invite(channel.0, info.uid).await;
 
             if let Some(url) = invite_code {
-                return HttpResponse::Ok().json(GuildInviteData {
+                return Ok(HttpResponse::Ok().json(GuildInviteData {
                     url,
                     cid: channel.0,
-                });
+                }));
             }
         }
     }
     debug!("Failed to fetch guild");
-    HttpResponse::NotFound().finish()
+    Err(ApiError::not_found(
+        "no_invite",
+        "Could not create an invite in any channel",
+    ))
+}
+
+/// The IPC API, served under /v1 and, for older callers, at the root
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(user_perms)
+        .service(user_perms_many)
+        .service(normal_roles)
+        .service(normal_roles_many)
+        .service(all_roles)
+        .service(flag_audit)
+        .service(flag_dry_run)
+        .service(getch)
+        .service(getch_many)
+        .service(send_message)
+        .service(edit_message)
+        .service(delete_message)
+        .service(guild_invite);
 }
 
 struct IpcAppData {
@@ -157,29 +588,159 @@ struct IpcAppData {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    std::env::set_var("RUST_LOG", "baypaw=debug,actix_web=info");
+    // Check everything up front so all problems can be fixed in one go
+    let config = config::Config::load();
+    let files = config::data_dir()
+        .map_err(|err| vec![err])
+        .and_then(|data_dir| config::DataFiles::load(&data_dir).map(|files| (data_dir, files)));
+
+    let (config, (data_dir, files)) = match (config, files) {
+        (Ok(config), Ok(files)) => (config, files),
+        (config, files) => {
+            eprintln!("Baypaw could not start due to configuration errors:");
+
+            for err in config.err().into_iter().chain(files.err()).flatten() {
+                eprintln!("  {}", err);
+            }
+
+            std::process::exit(1);
+        }
+    };
 
-    env_logger::init();
+    // Only fall back to our own log filter if the operator hasn't set RUST_LOG
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&config.log_filter))
+        .init();
 
-    let database = database::Database::new().await;
+    debug!("Loaded config: {:?}", config);
+
+    let database = database::Database::new(&config, files).await;
+
+    tokio::spawn(config::reload_on_sighup(database.live.clone(), data_dir));
+    let secret = database.secret.clone();
+    let metrics = database.metrics.clone();
     let app_data = web::Data::new(IpcAppData { database });
+    let shutdown_data = app_data.clone();
+
+    let server = HttpServer::new(move || {
+        let metrics = metrics.clone();
 
-    HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
+            // Bad ids and bodies get the same JSON errors as everything else
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|err, _| ApiError::bad_request(err.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|err, _| ApiError::bad_request(err.to_string()).into()),
+            )
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|err, _| ApiError::bad_request(err.to_string()).into()),
+            )
+            .wrap(auth::SharedSecret::new(secret.clone()))
+            .wrap_fn(move |req, srv| {
+                // Label by the matched pattern so every id doesn't get its own series
+                let route = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                let method = req.method().to_string();
+                let metrics = metrics.clone();
+                let start = Instant::now();
+
+                let fut = srv.call(req);
+
+                async move {
+                    let res = fut.await;
+
+                    let status = match res {
+                        Ok(ref res) => res.status(),
+                        Err(ref err) => err.as_response_error().status_code(),
+                    };
+
+                    metrics.observe_request(&route, &method, status.as_u16(), start.elapsed());
+
+                    res
+                }
+            })
             .wrap(actix_web::middleware::Logger::default())
-            .service(user_perms)
-            .service(normal_roles)
-            .service(getch)
-            .service(send_message)
-            .service(guild_invite)
+            .service(healthz)
+            .service(readyz)
+            .service(prometheus_metrics)
+            .service(web::scope("/v1").configure(routes))
+            // Unversioned paths for callers from before /v1
+            .configure(routes)
     })
-    .workers(6)
-    .bind(("127.0.0.1", 1234))
-    .unwrap()
-    .run()
-    .await
-    .unwrap();
+    .workers(config.workers)
+    .shutdown_timeout(config.shutdown_timeout);
 
-    Ok(())
+    let server = match config.socket {
+        Some(ref path) => server.listen_uds(bind_socket(path, config.socket_mode())?)?,
+        None => server.bind((config.bind.as_str(), config.port))?,
+    };
+
+    // On SIGTERM actix stops accepting requests and waits up to shutdown_timeout for
+    // the ones in flight before run returns
+    let res = server.run().await;
+
+    info!("IPC server stopped, shutting down");
+    shutdown_data.database.shutdown().await;
+
+    if let Some(ref path) = config.socket {
+        let _ = std::fs::remove_file(path);
+    }
+
+    res
+}
+
+/// Binds a unix socket at path, replacing any stale socket left behind by a previous
+/// run, and restricts it to the given mode before any requests can be accepted.
+/// The socket is bound and chmodded in a private directory next to path and only
+/// then moved into place, so nobody can connect while it still has umask permissions
+fn bind_socket(path: &Path, mode: u32) -> std::io::Result<UnixListener> {
+    // Only ever replace a socket so a typo in the path can't delete a real file
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a file path", path.display()),
+        )
+    })?;
+
+    let staging = path.with_file_name(format!(".baypaw-{}", std::process::id()));
+
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let staged = staging.join(file_name);
+
+    let res = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+
+    // Also removes the staged socket if something went wrong
+    std::fs::remove_dir_all(&staging)?;
+
+    let listener = res?;
+
+    debug!(
+        "Listening on unix socket {} with mode {:o}",
+        path.display(),
+        mode
+    );
+
+    Ok(listener)
 }
@@ -0,0 +1,148 @@
+// Prometheus metrics behind /metrics
+//
+// Everything lives in our own registry rather than the prometheus default one
+// so the endpoint only ever shows what baypaw itself records
+use crate::flags::SyncSource;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Where a getch lookup was answered from
+#[derive(Clone, Copy, Debug)]
+pub enum GetchTier {
+    MainCache,
+    ServersCache,
+    /// A user fetch_bot_1 fetched before
+    Redis,
+    /// A fresh request to Discord using fetch_bot_1
+    Fetch,
+    /// Discord says the user doesn't exist, either now or in redis
+    NotFound,
+    /// fetch_bot_1 failed
+    Error,
+}
+
+impl GetchTier {
+    fn as_str(&self) -> &'static str {
+        match self {
+            GetchTier::MainCache => "main_cache",
+            GetchTier::ServersCache => "servers_cache",
+            GetchTier::Redis => "redis",
+            GetchTier::Fetch => "fetch",
+            GetchTier::NotFound => "not_found",
+            GetchTier::Error => "error",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    getch: IntCounterVec,
+    invite_failures: IntCounter,
+    flag_sync_errors: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("baypaw_http_requests_total", "IPC requests handled"),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "baypaw_http_request_duration_seconds",
+                "Time taken to handle IPC requests",
+            ),
+            &["route", "method"],
+        )
+        .unwrap();
+
+        let getch = IntCounterVec::new(
+            Opts::new(
+                "baypaw_getch_total",
+                "getch lookups by where they were answered",
+            ),
+            &["tier"],
+        )
+        .unwrap();
+
+        let invite_failures = IntCounter::new(
+            "baypaw_invite_failures_total",
+            "Invites squirrelflight failed to create",
+        )
+        .unwrap();
+
+        let flag_sync_errors = IntCounterVec::new(
+            Opts::new("baypaw_flag_sync_errors_total", "Failed flag syncs"),
+            &["source"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(getch.clone())).unwrap();
+        registry
+            .register(Box::new(invite_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(flag_sync_errors.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            getch,
+            invite_failures,
+            flag_sync_errors,
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a handled request. route is the pattern it matched, such as
+    /// /getch/{id}, so ids don't each get their own series
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, took: Duration) {
+        self.requests
+            .with_label_values(&[route, method, &status.to_string()])
+            .inc();
+
+        self.request_duration
+            .with_label_values(&[route, method])
+            .observe(took.as_secs_f64());
+    }
+
+    pub fn getch(&self, tier: GetchTier) {
+        self.getch.with_label_values(&[tier.as_str()]).inc();
+    }
+
+    pub fn invite_failed(&self) {
+        self.invite_failures.inc();
+    }
+
+    pub fn flag_sync_failed(&self, source: SyncSource) {
+        self.flag_sync_errors
+            .with_label_values(&[source.as_str()])
+            .inc();
+    }
+
+    /// Everything recorded so far in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+
+        String::from_utf8(buf).unwrap()
+    }
+}
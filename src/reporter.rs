@@ -0,0 +1,162 @@
+// Reports errors to site_errors_channel on the main server
+//
+// Reports are sent as embeds from a background task so callers (including
+// HTTP handlers) never wait on Discord. The same error is only reported once
+// per error_report_window so a broken dependency can't flood the channel
+use crate::config::LiveConfig;
+use arc_swap::ArcSwap;
+use log::{debug, error};
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use serenity::model::Timestamp;
+use serenity::utils::Colour;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Discord's embed limits
+const TITLE_LIMIT: usize = 256;
+const DESCRIPTION_LIMIT: usize = 4096;
+const FIELD_NAME_LIMIT: usize = 256;
+const FIELD_VALUE_LIMIT: usize = 1024;
+const FIELDS_LIMIT: usize = 25;
+const EMBED_LIMIT: usize = 6000;
+
+/// An error along with what we were doing when it happened
+pub struct ErrorReport {
+    title: String,
+    error: String,
+    fields: Vec<(String, String)>,
+}
+
+impl ErrorReport {
+    pub fn new(title: impl Display, err: impl Debug) -> Self {
+        ErrorReport {
+            title: title.to_string(),
+            error: format!("{:?}", err),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Adds context such as the user id, role or query to the report
+    pub fn field(mut self, name: impl Display, value: impl Display) -> Self {
+        self.fields.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Two reports with the same key are duplicates
+    fn key(&self) -> String {
+        format!("{}\n{}", self.title, self.error)
+    }
+}
+
+pub struct ErrorReporter {
+    http: Arc<Http>,
+    live: Arc<ArcSwap<LiveConfig>>,
+    window: Duration,
+    /// When each error was last reported
+    recent: Mutex<HashMap<String, Instant>>,
+}
+
+impl ErrorReporter {
+    pub fn new(http: Arc<Http>, live: Arc<ArcSwap<LiveConfig>>, window: Duration) -> Self {
+        ErrorReporter {
+            http,
+            live,
+            window,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Logs the error and sends it to site_errors_channel unless it was already
+    /// reported recently
+    pub fn report(self: &Arc<Self>, report: ErrorReport) {
+        error!("{}: {}", report.title, report.error);
+
+        if !self.should_send(&report) {
+            debug!("Not reporting duplicate error: {}", report.title);
+            return;
+        }
+
+        let reporter = self.clone();
+        tokio::spawn(async move { reporter.send(report).await });
+    }
+
+    fn should_send(&self, report: &ErrorReport) -> bool {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+
+        recent.retain(|_, reported_at| now.duration_since(*reported_at) < self.window);
+
+        if recent.contains_key(&report.key()) {
+            return false;
+        }
+
+        recent.insert(report.key(), now);
+        true
+    }
+
+    async fn send(&self, report: ErrorReport) {
+        let site_errors_channel = self
+            .live
+            .load()
+            .discord
+            .channels
+            .site_errors_channel
+            .parse::<u64>();
+
+        let channel = match site_errors_channel {
+            Ok(channel) => ChannelId(channel),
+            Err(_) => return,
+        };
+
+        let title = truncate(&report.title, TITLE_LIMIT);
+        let description = format!(
+            "```rs\n{}```",
+            truncate(&report.error, DESCRIPTION_LIMIT - 10)
+        );
+
+        // The whole embed also has a limit so drop any fields that don't fit
+        let mut left = EMBED_LIMIT - title.chars().count() - description.chars().count();
+        let mut fields = Vec::new();
+
+        for (name, value) in report.fields.iter().take(FIELDS_LIMIT) {
+            let name = truncate(name, FIELD_NAME_LIMIT);
+            let value = truncate(value, FIELD_VALUE_LIMIT);
+            let len = name.chars().count() + value.chars().count();
+
+            if len > left {
+                break;
+            }
+
+            left -= len;
+            fields.push((name, value, true));
+        }
+
+        let res = channel
+            .send_message(&self.http, |m| {
+                m.embed(|e| {
+                    e.title(title)
+                        .description(description)
+                        .colour(Colour::RED)
+                        .timestamp(Timestamp::now())
+                        .fields(fields)
+                })
+            })
+            .await;
+
+        if let Err(e) = res {
+            error!("Error sending message to Discord, {:?}", e);
+        }
+    }
+}
+
+/// Cuts s down to at most limit characters, marking that it was cut
+fn truncate(s: &str, limit: usize) -> String {
+    if s.chars().count() <= limit {
+        return s.to_string();
+    }
+
+    s.chars().take(limit - 1).collect::<String>() + "…"
+}
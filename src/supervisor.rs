@@ -0,0 +1,185 @@
+// Keeps our bot clients running
+//
+// A serenity Client can't be started again once start returns as its shard
+// queuer has been shut down. So when a client stops we build a new one after a
+// backoff and swap it in. Its cache starts out empty and fills back up as the
+// new shards receive their guilds
+use crate::health::BotState;
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use serenity::cache::Cache;
+use serenity::client::bridge::gateway::ShardManager;
+use serenity::http::Http;
+use serenity::model::gateway::GatewayIntents;
+use serenity::prelude::*;
+use serenity::CacheAndHttp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// First wait before restarting a client, doubled on every restart in a row
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Sharding {
+    /// A single shard
+    Single,
+    /// As many shards as Discord recommends
+    Auto,
+}
+
+/// One of our bots along with whichever client is currently running it
+pub struct Bot {
+    pub name: &'static str,
+    pub state: Arc<BotState>,
+    cache_and_http: ArcSwap<CacheAndHttp>,
+    shards: ArcSwap<Mutex<ShardManager>>,
+    /// Set once we are shutting down so the client isn't restarted
+    stopping: AtomicBool,
+}
+
+impl Bot {
+    /// The cache of the current client
+    pub fn cache(&self) -> Arc<Cache> {
+        self.cache_and_http.load().cache.clone()
+    }
+
+    pub fn http(&self) -> Arc<Http> {
+        self.cache_and_http.load().http.clone()
+    }
+
+    pub fn shard_manager(&self) -> Arc<Mutex<ShardManager>> {
+        self.shards.load_full()
+    }
+
+    /// Stops the client for good, waiting for its shards to disconnect
+    pub async fn shutdown(&self) {
+        self.stopping.store(true, Ordering::Relaxed);
+
+        info!("Shutting down {} client", self.name);
+
+        self.shard_manager().lock().await.shutdown_all().await;
+    }
+
+    fn swap(&self, client: &Client) {
+        self.cache_and_http.store(client.cache_and_http.clone());
+        self.shards.store(client.shard_manager.clone());
+    }
+}
+
+/// Builds a client for a bot and starts it, restarting it with a backoff of up to
+/// max_backoff whenever it stops. state must be the one handler updates
+pub async fn start<H: EventHandler + 'static>(
+    name: &'static str,
+    token: String,
+    intents: GatewayIntents,
+    handler: H,
+    state: Arc<BotState>,
+    sharding: Sharding,
+    max_backoff: Duration,
+) -> Arc<Bot> {
+    let handler = Arc::new(handler);
+
+    let client = Client::builder(&token, intents)
+        .event_handler_arc(handler.clone())
+        .await
+        .unwrap();
+
+    let bot = Arc::new(Bot {
+        name,
+        state,
+        cache_and_http: ArcSwap::new(client.cache_and_http.clone()),
+        shards: ArcSwap::new(client.shard_manager.clone()),
+        stopping: AtomicBool::new(false),
+    });
+
+    tokio::spawn(supervise(
+        bot.clone(),
+        client,
+        token,
+        intents,
+        handler,
+        sharding,
+        max_backoff,
+    ));
+
+    bot
+}
+
+async fn supervise<H: EventHandler + 'static>(
+    bot: Arc<Bot>,
+    mut client: Client,
+    token: String,
+    intents: GatewayIntents,
+    handler: Arc<H>,
+    sharding: Sharding,
+    max_backoff: Duration,
+) {
+    let max_backoff = max_backoff.max(MIN_BACKOFF);
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        bot.state.running.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+
+        let res = match sharding {
+            Sharding::Single => client.start().await,
+            Sharding::Auto => client.start_autosharded().await,
+        };
+
+        bot.state.running.store(false, Ordering::Relaxed);
+        bot.state.ready.store(false, Ordering::Relaxed);
+
+        if bot.stopping.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let reason = match res {
+            Ok(()) => "Stopped".to_string(),
+            Err(err) => err.to_string(),
+        };
+
+        // A client that ran for a while before stopping gets a fresh backoff
+        if started.elapsed() > max_backoff {
+            backoff = MIN_BACKOFF;
+        }
+
+        error!(
+            "{} client stopped ({}), restarting in {:?}",
+            bot.name, reason, backoff
+        );
+
+        *bot.state.last_error.lock().unwrap() = Some(reason);
+
+        loop {
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(max_backoff);
+
+            if bot.stopping.load(Ordering::Relaxed) {
+                return;
+            }
+
+            match Client::builder(&token, intents)
+                .event_handler_arc(handler.clone())
+                .await
+            {
+                Ok(new) => {
+                    client = new;
+                    break;
+                }
+                Err(err) => {
+                    warn!(
+                        "Could not rebuild {} client, retrying in {:?}: {}",
+                        bot.name, backoff, err
+                    );
+                }
+            }
+        }
+
+        bot.swap(&client);
+        bot.state.restarts.fetch_add(1, Ordering::Relaxed);
+
+        info!("Restarting {} client", bot.name);
+    }
+}